@@ -6,33 +6,55 @@ fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
 }
 
+/// Setting this environment variable makes `Memory` emit a `perf` jit map at
+/// `/tmp/perf-<pid>.map` so profilers can symbolize executable segments.
+#[cfg(not(feature = "vec_memory"))]
+const PERF_MAP_ENV: &str = "CRANELIFT_JIT_PERF_MAP";
+
+/// Byte pattern used to poison freshly committed pages so that a stray jump
+/// into not-yet-populated space traps instead of executing garbage:
+/// `int3` (`0xCC`) on x86-64, `udf #0` (all-zero) on aarch64.
+#[cfg(all(not(feature = "vec_memory"), target_arch = "x86_64"))]
+const TRAP_FILL: u8 = 0xCC;
+#[cfg(all(not(feature = "vec_memory"), not(target_arch = "x86_64")))]
+const TRAP_FILL: u8 = 0x00;
+
+#[cfg(not(feature = "vec_memory"))]
 #[derive(Debug)]
 struct Segment {
     ptr: *mut u8,
     len: usize,
     position: usize,
     target_prot: region::Protection,
+    branch_protection: BranchProtection,
     finalized: bool,
+    /// Number of outstanding [`mark_writable`](Memory::mark_writable) re-opens
+    /// against this segment. While non-zero the segment has sub-ranges flipped
+    /// back to READ_WRITE for patching, so it must not be reused by `allocate`
+    /// (the un-patched remainder is still READ_EXECUTE) nor treated as dead by
+    /// `Drop` (it still hands out live code). Finalized stays `true` throughout;
+    /// a single bool cannot describe a partially re-opened segment.
+    patch_count: usize,
 }
 
+#[cfg(not(feature = "vec_memory"))]
 impl Segment {
-    fn new(ptr: *mut u8, len: usize, target_prot: region::Protection) -> Self {
-        let mut segment = Segment {
+    fn new(
+        ptr: *mut u8,
+        len: usize,
+        target_prot: region::Protection,
+        branch_protection: BranchProtection,
+    ) -> Self {
+        // Pages stay at `PROT_NONE` until `Memory::commit` crosses them; the
+        // segment only tracks bookkeeping here.
+        Segment {
             ptr,
             len,
             target_prot,
+            branch_protection,
             position: 0,
             finalized: false,
-        };
-        // set setgment to read-write for initialization
-        segment.set_rw();
-        segment
-    }
-
-    fn set_rw(&mut self) {
-        unsafe {
-            region::protect(self.ptr, self.len, region::Protection::READ_WRITE)
-                .expect("unable to change memory protection for jit memory segment");
+            patch_count: 0,
         }
     }
 
@@ -41,12 +63,18 @@ impl Segment {
             return;
         }
         unsafe {
-            region::protect(self.ptr, self.len, self.target_prot)
-                .expect("unable to change memory protection for jit memory segment");
+            self.protect_target();
         }
         self.finalized = true;
     }
 
+    /// Applies `target_prot` to the whole segment, OR-ing in the BTI guard bit
+    /// on aarch64 when `BranchProtection::BTI` is selected for executable
+    /// pages.
+    unsafe fn protect_target(&self) {
+        apply_target_protection(self.ptr, self.len, self.target_prot, self.branch_protection);
+    }
+
     fn allocate(&mut self, size: usize, align: usize) -> *mut u8 {
         assert!(self.has_space_for(size, align));
         self.position = align_up(self.position, align); // FIXME: this is incorrect for align > page size
@@ -60,8 +88,57 @@ impl Segment {
     }
 }
 
+/// Applies `target_prot` to `[ptr, ptr + len)`, OR-ing in the aarch64 BTI guard
+/// bit when executable pages request [`BranchProtection::BTI`]. Single source of
+/// truth shared by both the finalize path and
+/// [`mark_executable`](Memory::mark_executable) so the BTI branch can't drift.
+#[cfg(not(feature = "vec_memory"))]
+unsafe fn apply_target_protection(
+    ptr: *mut u8,
+    len: usize,
+    target_prot: region::Protection,
+    branch_protection: BranchProtection,
+) {
+    if target_prot == region::Protection::READ_EXECUTE
+        && branch_protection == BranchProtection::BTI
+    {
+        protect_read_execute_bti(ptr, len);
+    } else {
+        region::protect(ptr, len, target_prot)
+            .expect("unable to change memory protection for jit memory segment");
+    }
+}
+
+/// Applies `READ_EXECUTE` to a finalized segment with the aarch64 BTI guard
+/// bit (`PROT_BTI`) set, so indirect branches must land on a `BTI` landing-pad
+/// instruction or the CPU faults. A no-op fallback to plain `READ_EXECUTE` is
+/// used on architectures/kernels without BTI support.
+#[cfg(all(not(feature = "vec_memory"), target_arch = "aarch64", target_os = "linux"))]
+unsafe fn protect_read_execute_bti(ptr: *mut u8, len: usize) {
+    // PROT_BTI is not exposed by the `region` crate; set it with a raw
+    // `mprotect` alongside PROT_READ | PROT_EXEC.
+    const PROT_BTI: libc::c_int = 0x10;
+    let ret = libc::mprotect(
+        ptr.cast(),
+        len,
+        libc::PROT_READ | libc::PROT_EXEC | PROT_BTI,
+    );
+    if ret != 0 {
+        // Kernels or CPUs without BTI support reject PROT_BTI; fall back to
+        // plain READ_EXECUTE.
+        region::protect(ptr, len, region::Protection::READ_EXECUTE)
+            .expect("unable to change memory protection for jit memory segment");
+    }
+}
+
+#[cfg(all(not(feature = "vec_memory"), not(all(target_arch = "aarch64", target_os = "linux"))))]
+unsafe fn protect_read_execute_bti(ptr: *mut u8, len: usize) {
+    region::protect(ptr, len, region::Protection::READ_EXECUTE)
+        .expect("unable to change memory protection for jit memory segment");
+}
+
 /// Type of branch protection to apply to executable memory.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub(crate) enum BranchProtection {
     /// No protection.
     None,
@@ -75,36 +152,82 @@ pub(crate) enum BranchProtection {
 /// program's life.
 // TODO: docs
 // provides a contiguous memory area with properly managed protection flags.
+#[cfg(not(feature = "vec_memory"))]
 pub(crate) struct Memory {
+    /// Platform-neutral reservation backing the whole region. Kept alive for
+    /// the life of the `Memory`; dropped (unmapped) only by `free`. `None`
+    /// once freed.
+    allocation: Option<region::Allocation>,
+    /// When `perf` symbolization is requested (see `PERF_MAP_ENV`), the open
+    /// `/tmp/perf-<pid>.map` handle entries are appended to. Kept open for the
+    /// life of the `Memory` so entries accumulate; it is closed when the
+    /// `Memory` is dropped, even if the region itself is leaked.
+    perf_map: Option<std::fs::File>,
+    /// Branch-protection policy applied to executable segments at finalize
+    /// time.
+    branch_protection: BranchProtection,
     ptr: *mut u8,
     size: usize,
     position: usize,
+    /// High-water mark of pages that have been committed (flipped to
+    /// READ_WRITE and trap-filled). Physical memory grows with this, not with
+    /// `size`.
+    mapped_region_bytes: usize,
     segments: Vec<Segment>,
 }
 
+#[cfg(not(feature = "vec_memory"))]
 impl Memory {
-    pub(crate) fn new(_branch_protection: BranchProtection, reserve_size: usize) -> Self {
-        use nix::sys::mman::*;
+    pub(crate) fn new(branch_protection: BranchProtection, reserve_size: usize) -> Self {
         let size = align_up(reserve_size, region::page::size());
-        let ptr = unsafe {
-            mmap(
-                ptr::null_mut(),
-                size,
-                ProtFlags::PROT_NONE,
-                // ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-                MapFlags::MAP_PRIVATE | MapFlags::MAP_ANON,
-                -1,
-                0,
-            )
-            .unwrap() // TODO?
-        };
+        // Reserve the whole region with no access (PROT_NONE on unix,
+        // MEM_RESERVE+PAGE_NOACCESS on Windows); pages are committed lazily by
+        // `commit`.
+        let allocation = region::alloc(size, region::Protection::NONE)
+            .expect("unable to reserve jit memory region");
+        let ptr = allocation.as_ptr::<u8>() as *mut u8;
+
+        let perf_map = std::env::var_os(PERF_MAP_ENV).map(|_| {
+            let path = format!("/tmp/perf-{}.map", std::process::id());
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .expect("unable to open perf jit map")
+        });
 
         Self {
             segments: Vec::new(),
-            ptr: ptr as *mut u8,
+            allocation: Some(allocation),
+            perf_map,
+            branch_protection,
+            ptr,
             size,
             position: 0,
+            mapped_region_bytes: 0,
+        }
+    }
+
+    /// Commits physical memory up to the page covering `ptr + len`, advancing
+    /// the `mapped_region_bytes` high-water mark. Each newly-touched page is
+    /// flipped from `PROT_NONE` to READ_WRITE and poisoned with [`TRAP_FILL`]
+    /// so buggy control-flow transfers into unpopulated space crash
+    /// deterministically instead of executing garbage.
+    fn commit(&mut self, ptr: *mut u8, len: usize) {
+        let end = (ptr as usize - self.ptr as usize) + len;
+        let new_mark = region::page::ceil(end);
+        if new_mark <= self.mapped_region_bytes {
+            return;
         }
+        let start = self.mapped_region_bytes;
+        let commit_len = new_mark - start;
+        unsafe {
+            let commit_ptr = self.ptr.add(start);
+            region::protect(commit_ptr, commit_len, region::Protection::READ_WRITE)
+                .expect("unable to commit jit memory pages");
+            ptr::write_bytes(commit_ptr, TRAP_FILL, commit_len);
+        }
+        self.mapped_region_bytes = new_mark;
     }
 
     pub(crate) fn allocate_readonly(&mut self, size: usize, align: u64) -> io::Result<*mut u8> {
@@ -119,6 +242,21 @@ impl Memory {
         self.allocate(size, align as usize, region::Protection::READ_EXECUTE)
     }
 
+    /// Records a symbol for `[ptr, ptr + len)` in the `perf` jit map, if one is
+    /// being emitted (see [`PERF_MAP_ENV`]). One line is appended per call in
+    /// the `START SIZE NAME` format `perf` expects, with `START`/`SIZE` as
+    /// lowercase hex.
+    ///
+    /// The map format cannot represent reused addresses, so this is only
+    /// meaningful while the region is leaked (the default); entries written
+    /// before a `free` become stale once the addresses are reused.
+    pub(crate) fn register_symbol(&mut self, ptr: *mut u8, len: usize, name: &str) {
+        use std::io::Write;
+        if let Some(file) = &mut self.perf_map {
+            let _ = writeln!(file, "{:x} {:x} {}", ptr as usize, len, name);
+        }
+    }
+
     fn allocate(
         &mut self,
         size: usize,
@@ -128,29 +266,45 @@ impl Memory {
         // TODO: fast path without linear scan over segments?
 
         // can we fit this allocation into an existing segment
-        if let Some(segment) = self.segments.iter_mut().find(|seg| {
-            seg.target_prot == protection && !seg.finalized && seg.has_space_for(size, align)
-        }) {
-            return Ok(segment.allocate(size, align));
+        let existing = self
+            .segments
+            .iter_mut()
+            .find(|seg| {
+                seg.target_prot == protection
+                    && !seg.finalized
+                    && seg.patch_count == 0
+                    && seg.has_space_for(size, align)
+            })
+            .map(|segment| segment.allocate(size, align));
+        if let Some(ptr) = existing {
+            self.commit(ptr, size);
+            return Ok(ptr);
         }
 
         // can we resize the last segment?
-        if let Some(segment) = self.segments.iter_mut().last() {
-            if segment.target_prot == protection && !segment.finalized {
+        let resized = self.segments.iter_mut().last().and_then(|segment| {
+            if segment.target_prot == protection && !segment.finalized && segment.patch_count == 0 {
                 // resize
                 let additional_size = region::page::ceil(align_up(size, align));
-                assert!(self.position + additional_size <= self.size);
                 segment.len += additional_size;
-                segment.set_rw();
-                self.position += additional_size;
-                return Ok(segment.allocate(size, align));
+                Some((segment.allocate(size, align), additional_size))
+            } else {
+                None
             }
+        });
+        if let Some((ptr, additional_size)) = resized {
+            assert!(self.position + additional_size <= self.size);
+            self.position += additional_size;
+            self.commit(ptr, size);
+            return Ok(ptr);
         }
 
         // allocate new segment for size&align
         self.allocate_segment(align_up(size, align), protection);
         let i = self.segments.len() - 1;
-        Ok(self.segments[i].allocate(size, align))
+        let ptr = self.segments[i].allocate(size, align);
+        self.commit(ptr, size);
+        Ok(ptr)
     }
 
     fn allocate_segment(&mut self, size: usize, target_prot: region::Protection) {
@@ -158,36 +312,203 @@ impl Memory {
         let ptr = unsafe { self.ptr.add(self.position) };
         self.position += size;
         assert!(self.position <= self.size);
-        self.segments.push(Segment::new(ptr, size, target_prot));
+        self.segments
+            .push(Segment::new(ptr, size, target_prot, self.branch_protection));
     }
 
     /// TODO
     pub(crate) fn finalize(&mut self) {
-        for segment in &mut self.segments {
+        for i in 0..self.segments.len() {
+            let segment = &mut self.segments[i];
+            if segment.finalized {
+                continue;
+            }
+            // Commit (and trap-fill) any tail pages no allocation has reached
+            // yet, so they never receive the target protection while still
+            // unpopulated and a later `commit` never re-opens them.
+            let (seg_ptr, seg_len) = (segment.ptr, segment.len);
+            self.commit(seg_ptr, seg_len);
+            let segment = &mut self.segments[i];
             segment.finalize();
+            // Record each freshly-finalized executable segment in the `perf`
+            // jit map (a no-op unless `PERF_MAP_ENV` is set) so profilers can
+            // symbolize the region.
+            let symbol = if segment.target_prot == region::Protection::READ_EXECUTE {
+                Some((segment.ptr, segment.position))
+            } else {
+                None
+            };
+            if let Some((ptr, len)) = symbol {
+                self.register_symbol(ptr, len, &format!("jit_segment_{i}"));
+            }
+        }
+    }
+
+    /// Finds the segment fully containing `[ptr, ptr + len)`, finalized or not.
+    fn segment_covering(&mut self, ptr: *mut u8, len: usize) -> Option<&mut Segment> {
+        let start = ptr as usize;
+        let end = start + len;
+        self.segments.iter_mut().find(|seg| {
+            let seg_start = seg.ptr as usize;
+            start >= seg_start && end <= seg_start + seg.len
+        })
+    }
+
+    /// Re-opens already-finalized executable code for patching (relocation
+    /// fixups, inline-cache updates, tier-up stubs). The pages covering
+    /// `[ptr, ptr + len)` in the owning segment are flipped back to READ_WRITE
+    /// and the owning segment's outstanding-patch count is bumped. The segment
+    /// stays `finalized` — it still hands out live code — so `allocate` will
+    /// not hand the un-patched (still READ_EXECUTE) remainder to a new
+    /// allocation and `Drop` will not free it mid-patch. The range is rounded
+    /// out to page boundaries so a page is never left writable *and*
+    /// executable, preserving W^X at page granularity. Pair with
+    /// [`mark_executable`](Self::mark_executable).
+    pub(crate) fn mark_writable(&mut self, ptr: *mut u8, len: usize) {
+        let page = region::page::size();
+        let start = (ptr as usize) & !(page - 1);
+        let end = align_up(ptr as usize + len, page);
+        let segment = self
+            .segment_covering(ptr, len)
+            .expect("mark_writable: no segment covers the requested range");
+        unsafe {
+            region::protect(
+                start as *mut u8,
+                end - start,
+                region::Protection::READ_WRITE,
+            )
+            .expect("unable to re-protect jit segment as writable");
+        }
+        segment.patch_count += 1;
+    }
+
+    /// Restores READ_EXECUTE protection to code previously re-opened with
+    /// [`mark_writable`](Self::mark_writable), re-establishing W^X. The range is
+    /// rounded out to page boundaries and the owning segment's outstanding-patch
+    /// count is decremented; once it reaches zero the segment no longer counts
+    /// as being patched. A finalized segment is still never reused by
+    /// `allocate` nor freed on `Drop`.
+    pub(crate) fn mark_executable(&mut self, ptr: *mut u8, len: usize) {
+        let page = region::page::size();
+        let start = (ptr as usize) & !(page - 1);
+        let end = align_up(ptr as usize + len, page);
+        let segment = self
+            .segment_covering(ptr, len)
+            .expect("mark_executable: no segment covers the requested range");
+        let target_prot = segment.target_prot;
+        let branch_protection = segment.branch_protection;
+        unsafe {
+            apply_target_protection(start as *mut u8, end - start, target_prot, branch_protection);
         }
+        segment.patch_count = segment.patch_count.saturating_sub(1);
     }
 
     /// Frees the allocated memory region, which would be leaked otherwise.
     /// Likely to invalidate existing function pointers, causing unsafety.
     pub(crate) unsafe fn free(&mut self) {
-        if self.ptr == ptr::null_mut() {
+        if self.allocation.is_none() {
             return;
         }
         self.segments.clear();
-        use nix::sys::mman::*;
-        munmap(self.ptr.cast(), self.size).expect("failed to unmap jit memory region");
+        // Dropping the `Allocation` releases the reservation cross-platform
+        // (munmap on unix, VirtualFree on Windows).
+        drop(self.allocation.take());
         self.ptr = ptr::null_mut();
     }
 }
 
+#[cfg(not(feature = "vec_memory"))]
 impl Drop for Memory {
     fn drop(&mut self) {
-        let is_live = self.segments.iter().any(|seg| seg.finalized);
-        if !is_live && self.ptr != ptr::null_mut() {
+        let is_live = self
+            .segments
+            .iter()
+            .any(|seg| seg.finalized || seg.patch_count > 0);
+        if is_live {
+            // Some segment still hands out live function pointers; leak the
+            // reservation so they remain valid for the rest of the program.
+            std::mem::forget(self.allocation.take());
+        } else if self.allocation.is_some() {
             // memory is unused, we can free this region
             unsafe { self.free() };
-            panic!();
         }
     }
 }
+
+/// Safe `Vec<u8>`-backed [`Memory`] used when the `vec_memory` feature is
+/// enabled. It exposes the identical surface but serves every allocation from
+/// a single page-aligned buffer of `reserve_size` bytes (rounded up to whole
+/// pages) and performs no
+/// page-protection changes, which keeps `unsafe` to the bump-pointer arithmetic
+/// and makes the rest of the crate exercisable without touching real page
+/// protections (handy for tests and fuzzing). The buffer is allocated once and
+/// does not grow; an allocation past its end panics. Executable code placed
+/// here is never actually made executable; `finalize` is a protection no-op.
+#[cfg(feature = "vec_memory")]
+pub(crate) struct Memory {
+    buffer: Vec<u8>,
+    /// Offset of the page-aligned base within `buffer`.
+    base: usize,
+    position: usize,
+    len: usize,
+}
+
+#[cfg(feature = "vec_memory")]
+impl Memory {
+    pub(crate) fn new(_branch_protection: BranchProtection, reserve_size: usize) -> Self {
+        let page = region::page::size();
+        let len = align_up(reserve_size, page);
+        // Over-allocate by a page so we can hand out a page-aligned base
+        // regardless of where the `Vec`'s buffer lands.
+        let mut buffer = vec![0u8; len + page];
+        let addr = buffer.as_mut_ptr() as usize;
+        let base = align_up(addr, page) - addr;
+        Self {
+            buffer,
+            base,
+            position: 0,
+            len,
+        }
+    }
+
+    pub(crate) fn allocate_readonly(&mut self, size: usize, align: u64) -> io::Result<*mut u8> {
+        self.allocate(size, align as usize)
+    }
+
+    pub(crate) fn allocate_readwrite(&mut self, size: usize, align: u64) -> io::Result<*mut u8> {
+        self.allocate(size, align as usize)
+    }
+
+    pub(crate) fn allocate_readexec(&mut self, size: usize, align: u64) -> io::Result<*mut u8> {
+        self.allocate(size, align as usize)
+    }
+
+    fn allocate(&mut self, size: usize, align: usize) -> io::Result<*mut u8> {
+        let position = align_up(self.position, align);
+        assert!(position + size <= self.len, "vec_memory region exhausted");
+        let ptr = unsafe { self.buffer.as_mut_ptr().add(self.base + position) };
+        self.position = position + size;
+        Ok(ptr)
+    }
+
+    /// No-op: `Vec` memory carries no page protection to finalize.
+    pub(crate) fn finalize(&mut self) {}
+
+    /// No-op under the `Vec` backend; there is no perf map to emit.
+    pub(crate) fn register_symbol(&mut self, _ptr: *mut u8, _len: usize, _name: &str) {}
+
+    /// No-op: all `Vec` memory is already writable.
+    pub(crate) fn mark_writable(&mut self, _ptr: *mut u8, _len: usize) {}
+
+    /// No-op: the `Vec` backend never changes protection.
+    pub(crate) fn mark_executable(&mut self, _ptr: *mut u8, _len: usize) {}
+
+    /// Frees the backing buffer now; otherwise it is freed when the `Memory`
+    /// is dropped. Likely to invalidate existing function pointers, causing
+    /// unsafety.
+    pub(crate) unsafe fn free(&mut self) {
+        self.buffer = Vec::new();
+        self.position = 0;
+        self.len = 0;
+    }
+}